@@ -0,0 +1,69 @@
+use crate::MachineRegister;
+use anyhow::{bail, Result};
+
+/// Target architecture for unwinding. Parameterizes which machine registers
+/// are tracked, their DWARF register numbers, the CFA / return-address
+/// conventions used by the CFI, and the shape of the emitted C context.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    /// Picks the arch to target from the parsed object's machine type.
+    pub fn from_object(arch: object::Architecture) -> Result<Self> {
+        match arch {
+            object::Architecture::X86_64 => Ok(Self::X86_64),
+            object::Architecture::Aarch64 => Ok(Self::Aarch64),
+            other => bail!("unsupported architecture: {:?}", other),
+        }
+    }
+
+    /// DWARF register number of the frame-pointer register this arch's CFI
+    /// conventionally tracks (`rbp` on x86_64, `x29` on aarch64).
+    pub fn frame_pointer(&self) -> gimli::Register {
+        match self {
+            Self::X86_64 => gimli::X86_64::RBP,
+            Self::Aarch64 => gimli::AArch64::X29,
+        }
+    }
+
+    /// DWARF register number of the return-address column of a CFI row
+    /// (the pseudo `RA` column on x86_64, the link register `x30` on
+    /// aarch64, which has no separate pseudo column).
+    pub fn return_address(&self) -> gimli::Register {
+        match self {
+            Self::X86_64 => gimli::X86_64::RA,
+            Self::Aarch64 => gimli::AArch64::X30,
+        }
+    }
+
+    /// Maps a DWARF register number to the `MachineRegister` role it plays
+    /// on this arch, for registers referenced by a `DW_OP_bregN` inside a
+    /// location expression or by a `CfaRule::RegisterAndOffset`.
+    pub fn machine_register(&self, register: gimli::Register) -> Option<MachineRegister> {
+        match self {
+            Self::X86_64 => match register {
+                gimli::X86_64::RSP => Some(MachineRegister::Sp),
+                gimli::X86_64::RBP => Some(MachineRegister::Fp),
+                gimli::X86_64::RA => Some(MachineRegister::Ra),
+                _ => None,
+            },
+            Self::Aarch64 => match register {
+                gimli::AArch64::SP => Some(MachineRegister::Sp),
+                gimli::AArch64::X29 => Some(MachineRegister::Fp),
+                gimli::AArch64::X30 => Some(MachineRegister::Ra),
+                _ => None,
+            },
+        }
+    }
+
+    /// Field names of the emitted `unwind_context_t`, as `(ip, sp, fp)`.
+    pub fn ctx_fields(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Self::X86_64 => ("rip", "rsp", "rbp"),
+            Self::Aarch64 => ("pc", "sp", "fp"),
+        }
+    }
+}
@@ -1,12 +1,11 @@
+use crate::Arch;
 use anyhow::Result;
-use gimli::{
-    CfaRule, NativeEndian, Reader, RegisterRule, UninitializedUnwindContext, UnwindSection,
-};
+use gimli::{CfaRule, Encoding, NativeEndian, Reader, RegisterRule, UnwindContext, UnwindSection};
 use object::{Object, ObjectSection};
 use std::path::Path;
 
 /// Holds a single dwarf register value.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum Register {
     /// Undefined register. The value will be defined at some
     /// later IP in the same DIE.
@@ -15,10 +14,15 @@ pub enum Register {
     Register(MachineRegister, isize),
     /// Value stored at some offset from `CFA`.
     CfaOffset(isize),
-    /// Value is the evaluation of the standard PLT
-    /// expression, ie `((rip & 15) >= 11) >> 3 + rsp`.
-    /// This is hardcoded because it is a common expression.
-    PltExpr,
+    /// Value is `CFA + offset` itself, rather than the value stored there.
+    CfaValue(isize),
+    /// Value is `*(expr)`, where `expr` is the evaluation of a raw DWARF
+    /// location expression (`RegisterRule::Expression`).
+    Expr(Vec<u8>, Encoding),
+    /// Value is the evaluation of a raw DWARF value expression
+    /// (`RegisterRule::ValExpression`, or the CFA's `CfaRule::Expression`),
+    /// used directly, without a deref.
+    ValExpr(Vec<u8>, Encoding),
     /// This type of register is not supported.
     Unimplemented,
 }
@@ -45,47 +49,39 @@ impl std::fmt::Display for Register {
                 let op = if *offset >= 0 { "+" } else { "" };
                 write!(f, "cfa{}{}", op, offset)
             }
-            Self::PltExpr => write!(f, "plt"),
+            Self::CfaValue(offset) => {
+                let op = if *offset >= 0 { "+" } else { "" };
+                write!(f, "cfaval{}{}", op, offset)
+            }
+            Self::Expr(..) => write!(f, "expr"),
+            Self::ValExpr(..) => write!(f, "valexpr"),
             Self::Unimplemented => write!(f, "unimpl"),
         }
     }
 }
 
-/// A machine register (eg. %rip) among the supported ones (x86_64 only for now).
+/// A machine register role tracked by the unwinder, independent of arch:
+/// the stack/frame pointer conventionally used as the CFA register, the
+/// frame pointer, and the return address.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum MachineRegister {
-    Rsp,
-    Rbp,
-    //Rbx,
+    Sp,
+    Fp,
     Ra,
 }
 
-impl From<gimli::Register> for MachineRegister {
-    fn from(reg: gimli::Register) -> Self {
-        match reg {
-            gimli::X86_64::RSP => Self::Rsp,
-            gimli::X86_64::RBP => Self::Rbp,
-            //gimli::X86_64::RBX => Self::Rbx,
-            gimli::X86_64::RA => Self::Ra,
-            _ => todo!(),
-        }
-    }
-}
-
 impl std::fmt::Display for MachineRegister {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        use MachineRegister::*;
         match self {
-            Rsp => write!(f, "rsp"),
-            Rbp => write!(f, "rbp"),
-            //Rbx => write!(f, "rbx"),
-            Ra => write!(f, "ra"),
+            Self::Sp => write!(f, "sp"),
+            Self::Fp => write!(f, "fp"),
+            Self::Ra => write!(f, "ra"),
         }
     }
 }
 
 /// Row of a FDE.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct UnwindTableRow {
     /// Instruction pointer start range (inclusive).
     pub start_address: usize,
@@ -93,10 +89,8 @@ pub struct UnwindTableRow {
     pub end_address: usize,
     /// Canonical frame address.
     pub cfa: Register,
-    /// Base pointer register.
-    pub rbp: Register,
-    // /// RBX, sometimes used for unwinding.
-    // pub rbx: Register,
+    /// Frame pointer register (`rbp` on x86_64, `x29` on aarch64).
+    pub fp: Register,
     /// Return address.
     pub ra: Register,
 }
@@ -104,35 +98,68 @@ pub struct UnwindTableRow {
 impl UnwindTableRow {
     pub fn parse<R: Reader>(
         row: &gimli::UnwindTableRow<R>,
-        _encoding: gimli::Encoding,
+        encoding: gimli::Encoding,
+        arch: Arch,
     ) -> Result<Self> {
         Ok(Self {
             start_address: row.start_address() as _,
             end_address: row.end_address() as _,
             cfa: match row.cfa() {
                 CfaRule::RegisterAndOffset { register, offset } => {
-                    Register::Register((*register).into(), *offset as _)
+                    match arch.machine_register(*register) {
+                        Some(mreg) => Register::Register(mreg, *offset as _),
+                        None => Register::Unimplemented,
+                    }
                 }
-                CfaRule::Expression(_expr) => {
-                    // TODO check it is always PltExpr
-                    Register::PltExpr
+                // The expression evaluates directly to the CFA value, not to
+                // a location holding it, so it must not be dereferenced.
+                CfaRule::Expression(expr) => {
+                    Register::ValExpr(expr.0.to_slice()?.into_owned(), encoding)
                 }
             },
-            rbp: match row.register(gimli::X86_64::RBP) {
-                RegisterRule::Undefined => Register::Undefined,
-                RegisterRule::Offset(offset) => Register::CfaOffset(offset as _),
-                _ => Register::Unimplemented,
-            },
-            /*rbx: match row.register(gimli::X86_64::RBX) {
-                RegisterRule::Undefined => Register::Undefined,
-                RegisterRule::Offset(offset) => Register::CfaOffset(offset as _),
-                _ => Register::Unimplemented,
-            },*/
-            ra: match row.register(gimli::X86_64::RA) {
-                RegisterRule::Undefined => Register::Undefined,
-                RegisterRule::Offset(offset) => Register::CfaOffset(offset as _),
-                _ => Register::Unimplemented,
+            fp: Self::register_rule(
+                row.register(arch.frame_pointer()),
+                encoding,
+                arch,
+                MachineRegister::Fp,
+            )?,
+            ra: Self::register_rule(
+                row.register(arch.return_address()),
+                encoding,
+                arch,
+                MachineRegister::Ra,
+            )?,
+        })
+    }
+
+    /// Translates a gimli `RegisterRule` for the register tracked as `slot`
+    /// into our `Register` value.
+    fn register_rule<R: Reader>(
+        rule: RegisterRule<R>,
+        encoding: gimli::Encoding,
+        arch: Arch,
+        slot: MachineRegister,
+    ) -> Result<Register> {
+        Ok(match rule {
+            RegisterRule::Undefined => Register::Undefined,
+            // The register's value is unchanged from the callee, ie. it
+            // still lives wherever the running context tracks `slot`.
+            RegisterRule::SameValue => Register::Register(slot, 0),
+            RegisterRule::Offset(offset) => Register::CfaOffset(offset as _),
+            RegisterRule::ValOffset(offset) => Register::CfaValue(offset as _),
+            RegisterRule::Register(other) => match arch.machine_register(other) {
+                Some(mreg) => Register::Register(mreg, 0),
+                None => Register::Unimplemented,
             },
+            RegisterRule::Expression(expr) => {
+                Register::Expr(expr.0.to_slice()?.into_owned(), encoding)
+            }
+            RegisterRule::ValExpression(expr) => {
+                Register::ValExpr(expr.0.to_slice()?.into_owned(), encoding)
+            }
+            // No arch-specific default beyond "unchanged" is defined for
+            // either arch we support, so treat it the same as `SameValue`.
+            RegisterRule::Architectural => Register::Register(slot, 0),
         })
     }
 }
@@ -145,8 +172,7 @@ impl std::fmt::Display for UnwindTableRow {
             self.start_address,
             self.end_address,
             self.cfa.to_string(),
-            self.rbp.to_string(),
-            //self.rbx.to_string(),
+            self.fp.to_string(),
             self.ra.to_string()
         )
     }
@@ -155,6 +181,7 @@ impl std::fmt::Display for UnwindTableRow {
 /// Unwind table.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct UnwindTable {
+    pub arch: Arch,
     pub rows: Vec<UnwindTableRow>,
 }
 
@@ -163,11 +190,7 @@ impl UnwindTable {
         let file = std::fs::File::open(path)?;
         let file = unsafe { memmap::Mmap::map(&file) }?;
         let file = object::File::parse(&*file)?;
-
-        let section = file.section_by_name(".eh_frame").unwrap();
-        let data = section.uncompressed_data()?;
-        let mut eh_frame = gimli::EhFrame::new(&data, NativeEndian);
-        eh_frame.set_address_size(std::mem::size_of::<usize>() as _);
+        let arch = Arch::from_object(file.architecture())?;
 
         let mut bases = gimli::BaseAddresses::default();
         if let Some(section) = file.section_by_name(".eh_frame_hdr") {
@@ -183,34 +206,70 @@ impl UnwindTable {
             bases = bases.set_got(section.address());
         }
 
-        let mut ctx = UninitializedUnwindContext::new();
-        let mut entries = eh_frame.entries(&bases);
+        // `.eh_frame` rows win when both sections describe the same range;
+        // `.debug_frame` (no augmentation/LSDA handling, different CIE
+        // encoding) only fills in coverage gaps, e.g. split-debug objects or
+        // binaries built without exception tables.
         let mut rows = vec![];
-        while let Some(entry) = entries.next()? {
-            match entry {
-                gimli::CieOrFde::Cie(_) => {}
-                gimli::CieOrFde::Fde(partial) => {
-                    let fde = partial.parse(|_, bases, o| eh_frame.cie_from_offset(bases, o))?;
-                    let encoding = fde.cie().encoding();
-                    let mut table = fde.rows(&eh_frame, &bases, &mut ctx)?;
-                    while let Some(row) = table.next_row()? {
-                        rows.push(UnwindTableRow::parse(row, encoding)?);
-                    }
+        if let Some(section) = file.section_by_name(".eh_frame") {
+            let data = section.uncompressed_data()?;
+            let mut eh_frame = gimli::EhFrame::new(&data, NativeEndian);
+            eh_frame.set_address_size(std::mem::size_of::<usize>() as _);
+            rows.extend(parse_unwind_section(&eh_frame, &bases, arch)?);
+        }
+        if let Some(section) = file.section_by_name(".debug_frame") {
+            let data = section.uncompressed_data()?;
+            let mut debug_frame = gimli::DebugFrame::new(&data, NativeEndian);
+            debug_frame.set_address_size(std::mem::size_of::<usize>() as _);
+            for row in parse_unwind_section(&debug_frame, &bases, arch)? {
+                let covered = rows.iter().any(|r: &UnwindTableRow| {
+                    row.start_address < r.end_address && r.start_address < row.end_address
+                });
+                if !covered {
+                    rows.push(row);
                 }
             }
         }
         rows.sort_unstable_by_key(|row| row.start_address);
-        Ok(Self { rows })
+        Ok(Self { arch, rows })
     }
 }
 
+/// Walks every FDE row of a `.eh_frame`/`.debug_frame`-like section. Generic
+/// over `gimli::UnwindSection` so the two formats share one code path despite
+/// differing in CIE encoding (address size, augmentation/LSDA handling,
+/// initial-length and pointer encodings).
+fn parse_unwind_section<R, S>(
+    section: &S,
+    bases: &gimli::BaseAddresses,
+    arch: Arch,
+) -> Result<Vec<UnwindTableRow>>
+where
+    R: Reader,
+    S: UnwindSection<R>,
+{
+    let mut ctx = Box::new(UnwindContext::new());
+    let mut entries = section.entries(bases);
+    let mut rows = vec![];
+    while let Some(entry) = entries.next()? {
+        match entry {
+            gimli::CieOrFde::Cie(_) => {}
+            gimli::CieOrFde::Fde(partial) => {
+                let fde = partial.parse(|_, bases, o| section.cie_from_offset(bases, o))?;
+                let encoding = fde.cie().encoding();
+                let mut table = fde.rows(section, bases, &mut ctx)?;
+                while let Some(row) = table.next_row()? {
+                    rows.push(UnwindTableRow::parse(row, encoding, arch)?);
+                }
+            }
+        }
+    }
+    Ok(rows)
+}
+
 impl std::fmt::Display for UnwindTable {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(
-            f,
-            "{:18} {:8} {:8} {:8}",
-            "ip", "cfa", "rbp", "ra"
-        )?;
+        writeln!(f, "{:18} {:8} {:8} {:8}", "ip", "cfa", "fp", "ra")?;
         for row in &self.rows {
             writeln!(f, "{}", row)?;
         }
@@ -1,35 +1,8 @@
-use crate::{UnwindTable, UnwindTableRow, Register};
-use anyhow::Result;
+use crate::{Arch, Register, UnwindTable, UnwindTableRow};
+use anyhow::{bail, Result};
+use gimli::{EndianSlice, NativeEndian, Operation};
 use std::io::Write;
 
-const PRE: &str = r#"
-#include <assert.h>
-#include <stdint.h>
-
-typedef enum {
-    UNWF_RIP=0,
-    UNWF_RSP=1,
-    UNWF_RBP=2,
-    UNWF_RBX=3,
-    UNWF_ERROR=7,
-} unwind_flags_t;
-
-typedef struct {
-    uint8_t flags;
-    uintptr_t rip, rsp, rbp, rbx;
-} unwind_context_t;
-
-typedef uintptr_t (*deref_func_t)(uintptr_t);
-
-typedef unwind_context_t (*_fde_func_t)(unwind_context_t, uintptr_t);
-typedef unwind_context_t (*_fde_func_with_deref_t)(
-    unwind_context_t,
-    uintptr_t,
-    deref_func_t);
-
-void _eh_elf(unwind_context_t ctx, unwind_context_t *out_ctx, uintptr_t pc, deref_func_t deref) {
-"#;
-
 const POST: &str = r#"
     out_ctx->flags = 7; // UNWF_ERROR
     return;
@@ -38,33 +11,83 @@ const POST: &str = r#"
 
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct UnwindFlags {
-    rip: bool,
-    rsp: bool,
-    rbp: bool,
-    rbx: bool,
+    ip: bool,
+    sp: bool,
+    fp: bool,
     error: bool,
 }
 
 impl From<UnwindFlags> for u8 {
     fn from(flags: UnwindFlags) -> Self {
-        ((flags.rip as u8) << 0) |
-        ((flags.rsp as u8) << 1) |
-        ((flags.rbp as u8) << 2) |
-        ((flags.rbx as u8) << 3) |
-        ((flags.error as u8) << 7)
+        ((flags.ip as u8) << 0)
+            | ((flags.sp as u8) << 1)
+            | ((flags.fp as u8) << 2)
+            | ((flags.error as u8) << 7)
     }
 }
 
 impl UnwindTable {
-    pub fn gen<W: Write>(&self, w: &mut W) -> Result<()> {
-        w.write_all(PRE.as_bytes())?;
-        gen_rows(w, &self.rows)?;
+    /// Generates the `_eh_elf` unwinder. When `checked` is set, each row also
+    /// emits defensive checks that catch a bad table or a corrupted stack
+    /// sending the walk backwards or in place, instead of looping forever.
+    pub fn gen<W: Write>(&self, w: &mut W, checked: bool) -> Result<()> {
+        gen_pre(w, self.arch)?;
+        if self.rows.is_empty() {
+            // No FDE rows at all (e.g. a stripped binary with no unwind
+            // info) — there's nothing to binary-search over.
+            let error = u8::from(UnwindFlags {
+                error: true,
+                ..Default::default()
+            });
+            writeln!(w, "out_ctx->flags = {}u;", error)?;
+            writeln!(w, "return;")?;
+        } else {
+            gen_rows(w, &self.rows, self.arch, checked)?;
+        }
         w.write_all(POST.as_bytes())?;
         Ok(())
     }
 }
 
-fn gen_rows<W: Write>(w: &mut W, rows: &[UnwindTableRow]) -> Result<()> {
+/// Emits the arch-appropriate preamble: the `unwind_context_t` struct (its
+/// field names follow the arch's own register names), the shared
+/// `unwind_flags_t` bit layout, and the `_eh_elf` function signature.
+fn gen_pre<W: Write>(w: &mut W, arch: Arch) -> Result<()> {
+    let (ip, sp, fp) = arch.ctx_fields();
+    writeln!(w, "#include <assert.h>")?;
+    writeln!(w, "#include <stdint.h>")?;
+    writeln!(w)?;
+    writeln!(w, "typedef enum {{")?;
+    writeln!(w, "    UNWF_IP=0,")?;
+    writeln!(w, "    UNWF_SP=1,")?;
+    writeln!(w, "    UNWF_FP=2,")?;
+    writeln!(w, "    UNWF_ERROR=7,")?;
+    writeln!(w, "}} unwind_flags_t;")?;
+    writeln!(w)?;
+    writeln!(w, "typedef struct {{")?;
+    writeln!(w, "    uint8_t flags;")?;
+    writeln!(w, "    uintptr_t {}, {}, {};", ip, sp, fp)?;
+    writeln!(w, "}} unwind_context_t;")?;
+    writeln!(w)?;
+    writeln!(w, "typedef uintptr_t (*deref_func_t)(uintptr_t);")?;
+    writeln!(w)?;
+    writeln!(
+        w,
+        "typedef unwind_context_t (*_fde_func_t)(unwind_context_t, uintptr_t);"
+    )?;
+    writeln!(w, "typedef unwind_context_t (*_fde_func_with_deref_t)(")?;
+    writeln!(w, "    unwind_context_t,")?;
+    writeln!(w, "    uintptr_t,")?;
+    writeln!(w, "    deref_func_t);")?;
+    writeln!(w)?;
+    writeln!(
+        w,
+        "void _eh_elf(unwind_context_t ctx, unwind_context_t *out_ctx, uintptr_t pc, deref_func_t deref) {{"
+    )?;
+    Ok(())
+}
+
+fn gen_rows<W: Write>(w: &mut W, rows: &[UnwindTableRow], arch: Arch, checked: bool) -> Result<()> {
     if rows.len() > 1 {
         let (a, b) = rows.split_at(rows.len() / 2);
         writeln!(
@@ -73,18 +96,19 @@ fn gen_rows<W: Write>(w: &mut W, rows: &[UnwindTableRow]) -> Result<()> {
             a.first().unwrap().start_address,
             a.last().unwrap().end_address,
         )?;
-        gen_rows(w, a)?;
+        gen_rows(w, a, arch, checked)?;
         writeln!(w, "}} else {{")?;
-        gen_rows(w, b)?;
+        gen_rows(w, b, arch, checked)?;
         writeln!(w, "}}")?;
     } else {
-        rows[0].gen(w)?;
+        rows[0].gen(w, arch, checked)?;
     }
     Ok(())
 }
 
 impl UnwindTableRow {
-    pub fn gen<W: Write>(&self, w: &mut W) -> Result<()> {
+    pub fn gen<W: Write>(&self, w: &mut W, arch: Arch, checked: bool) -> Result<()> {
+        let (ip_field, sp_field, fp_field) = arch.ctx_fields();
         let mut flags = UnwindFlags::default();
         if !self.ra.is_implemented() {
             // RA might be undefined (last frame), but if it is defined and we
@@ -92,49 +116,191 @@ impl UnwindTableRow {
             flags.error = true;
         }
         if self.cfa.is_implemented() {
-            flags.rsp = true;
-            write!(w, "out_ctx->rsp = ")?;
-            self.cfa.gen(w)?;
-            write!(w, ";\n")?;
+            match gen_expr(&self.cfa, arch) {
+                Some(expr) => {
+                    flags.sp = true;
+                    writeln!(w, "out_ctx->{} = {};", sp_field, expr)?;
+                }
+                None => flags.error = true,
+            }
         } else {
-            // rsp is required (CFA)
+            // The CFA register is required.
             flags.error = true;
         }
-        if self.rbp.is_defined() {
-            flags.rbp = true;
-            write!(w, "out_ctx->rbp = ")?;
-            self.rbp.gen(w)?;
-            write!(w, ";\n")?;
+        if self.fp.is_defined() {
+            match gen_expr(&self.fp, arch) {
+                Some(expr) => {
+                    flags.fp = true;
+                    writeln!(w, "out_ctx->{} = {};", fp_field, expr)?;
+                }
+                None => flags.error = true,
+            }
         }
         if self.ra.is_defined() {
-            flags.rip = true;
-            write!(w, "out_ctx->rip = ")?;
-            self.ra.gen(w)?;
-            write!(w, ";\n")?;
+            match gen_expr(&self.ra, arch) {
+                Some(expr) => {
+                    flags.ip = true;
+                    writeln!(w, "out_ctx->{} = {};", ip_field, expr)?;
+                }
+                None => flags.error = true,
+            }
+        }
+        if checked {
+            let error = u8::from(UnwindFlags {
+                error: true,
+                ..Default::default()
+            });
+            if flags.sp {
+                // Stack grows down, so the caller's CFA must be strictly
+                // above the callee's, or the walk never advances.
+                writeln!(
+                    w,
+                    "if (!(out_ctx->{sp} > ctx.{sp})) {{ out_ctx->flags = {error}u; return; }}",
+                    sp = sp_field,
+                    error = error,
+                )?;
+            }
+            if flags.ip {
+                writeln!(
+                    w,
+                    "if (out_ctx->{ip} == ctx.{ip}) {{ out_ctx->flags = {error}u; return; }}",
+                    ip = ip_field,
+                    error = error,
+                )?;
+            }
         }
-        /*if row.rbx.is_defined() {
-            flags.rbx = true;
-            writeln!(w, "out_ctx->rbx = {};\n", gen_of_reg(row.rbx))?;
-        }*/
         writeln!(w, "out_ctx->flags = {}u;", u8::from(flags))?;
         writeln!(w, "return;")?;
         Ok(())
     }
 }
 
+/// Renders a register's value as a C expression, or `None` if it turned out
+/// to use a DWARF opcode we don't translate (the row is then flagged as an
+/// error instead of emitting a partial expression).
+fn gen_expr(reg: &Register, arch: Arch) -> Option<String> {
+    let mut buf = Vec::new();
+    reg.gen(&mut buf, arch).ok()?;
+    String::from_utf8(buf).ok()
+}
+
 impl Register {
-    pub fn gen<W: Write>(&self, w: &mut W) -> Result<()> {
+    pub fn gen<W: Write>(&self, w: &mut W, arch: Arch) -> Result<()> {
         match self {
             Self::CfaOffset(offset) => {
-                write!(w, "deref(out_ctx->rsp + {})", offset)?
+                write!(w, "deref(out_ctx->{} + {})", arch.ctx_fields().1, offset)?
+            }
+            Self::CfaValue(offset) => {
+                write!(w, "out_ctx->{} + {}", arch.ctx_fields().1, offset)?
             }
             Self::Register(reg, offset) => {
-                write!(w, "ctx.{} + {}", reg, offset)?
+                write!(w, "ctx.{} + {}", ctx_field(arch, *reg), offset)?
+            }
+            Self::Expr(bytes, encoding) => {
+                write!(w, "deref({})", eval_dwarf_expr(bytes, *encoding, arch)?)?
+            }
+            Self::ValExpr(bytes, encoding) => {
+                write!(w, "{}", eval_dwarf_expr(bytes, *encoding, arch)?)?
             }
-            Self::PltExpr => write!(w, "(((ctx.rip & 15) >= 11) ? 8 : 0) + ctx.rsp")?,
             Self::Undefined => unreachable!(),
             Self::Unimplemented => unreachable!(),
         }
         Ok(())
     }
 }
+
+/// Maps a tracked `MachineRegister` role to its field in `unwind_context_t`.
+fn ctx_field(arch: Arch, mreg: crate::MachineRegister) -> &'static str {
+    let (ip, sp, fp) = arch.ctx_fields();
+    match mreg {
+        crate::MachineRegister::Sp => sp,
+        crate::MachineRegister::Fp => fp,
+        crate::MachineRegister::Ra => ip,
+    }
+}
+
+fn pop(stack: &mut Vec<String>) -> Result<String> {
+    stack.pop().ok_or_else(|| anyhow::anyhow!("DWARF expression: stack underflow"))
+}
+
+/// Compiles a DWARF stack-machine expression into a single C expression,
+/// by walking its opcodes as a compile-time stack of C expression strings.
+/// Opcodes we don't translate bail out, which callers turn into an error row.
+fn eval_dwarf_expr(bytes: &[u8], encoding: gimli::Encoding, arch: Arch) -> Result<String> {
+    let reader = EndianSlice::new(bytes, NativeEndian);
+    let expr = gimli::Expression(reader);
+    let mut ops = expr.operations(encoding);
+    let mut stack: Vec<String> = vec![];
+    while let Some(op) = ops.next()? {
+        match op {
+            Operation::UnsignedConstant { value } => stack.push(format!("{}", value)),
+            Operation::SignedConstant { value } => stack.push(format!("{}", value)),
+            Operation::RegisterOffset {
+                register, offset, ..
+            } => {
+                let mreg = arch
+                    .machine_register(register)
+                    .ok_or_else(|| anyhow::anyhow!("unsupported register in DWARF expression"))?;
+                stack.push(format!("(ctx.{} + {})", ctx_field(arch, mreg), offset));
+            }
+            Operation::PlusConstant { value } => {
+                let a = pop(&mut stack)?;
+                stack.push(format!("({} + {})", a, value));
+            }
+            Operation::Plus => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                stack.push(format!("({} + {})", a, b));
+            }
+            Operation::Minus => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                stack.push(format!("({} - {})", a, b));
+            }
+            Operation::And => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                stack.push(format!("({} & {})", a, b));
+            }
+            Operation::Shl => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                stack.push(format!("({} << {})", a, b));
+            }
+            Operation::Shr => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                stack.push(format!("({} >> {})", a, b));
+            }
+            Operation::Ge => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                stack.push(format!("({} >= {})", a, b));
+            }
+            Operation::Pick { index } => {
+                let i = stack
+                    .len()
+                    .checked_sub(1 + index as usize)
+                    .ok_or_else(|| anyhow::anyhow!("DW_OP_pick: stack underflow"))?;
+                let value = stack[i].clone();
+                stack.push(value);
+            }
+            Operation::Swap => {
+                let len = stack.len();
+                if len < 2 {
+                    bail!("DW_OP_swap: stack underflow");
+                }
+                stack.swap(len - 1, len - 2);
+            }
+            Operation::Drop => {
+                pop(&mut stack)?;
+            }
+            Operation::Deref { .. } => {
+                let a = pop(&mut stack)?;
+                stack.push(format!("deref({})", a));
+            }
+            _ => bail!("unsupported opcode in DWARF expression"),
+        }
+    }
+    pop(&mut stack)
+}
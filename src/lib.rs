@@ -0,0 +1,7 @@
+mod arch;
+mod ast;
+mod gen;
+mod table;
+
+pub use arch::Arch;
+pub use ast::*;
@@ -12,7 +12,7 @@ fn main() -> Result<()> {
 
     let table = UnwindTable::parse(input)?;
     let mut eh_elf = std::fs::File::create(&output_c)?;
-    table.gen(&mut eh_elf)?;
+    table.gen(&mut eh_elf, true)?;
 
     let output = Command::new("clang")
         .arg(output_c)
@@ -0,0 +1,359 @@
+use crate::{Arch, MachineRegister, Register, UnwindTable};
+use anyhow::Result;
+use std::io::Write;
+
+/// Tag of a compact per-register record, interpreted at runtime by
+/// `_eh_elf_reg` in the generated C.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum RegTag {
+    Undefined = 0,
+    CfaDeref = 1,
+    CfaVal = 2,
+    RegOffset = 3,
+    ExprDeref = 4,
+    ExprVal = 5,
+    Unimplemented = 6,
+}
+
+/// One register's compact encoding: a tag, a machine-register id (only
+/// meaningful for `RegOffset`), and a 64-bit operand — an offset, or a
+/// `(blob_offset << 32) | blob_len` pair into the shared expression blob.
+struct RegRecord {
+    tag: RegTag,
+    reg: u8,
+    operand: i64,
+}
+
+fn reg_id(mreg: MachineRegister) -> u8 {
+    match mreg {
+        MachineRegister::Sp => 0,
+        MachineRegister::Fp => 1,
+        MachineRegister::Ra => 2,
+    }
+}
+
+fn encode_register(reg: &Register, exprs: &mut Vec<u8>) -> RegRecord {
+    match reg {
+        Register::Undefined => RegRecord {
+            tag: RegTag::Undefined,
+            reg: 0,
+            operand: 0,
+        },
+        Register::CfaOffset(offset) => RegRecord {
+            tag: RegTag::CfaDeref,
+            reg: 0,
+            operand: *offset as i64,
+        },
+        Register::CfaValue(offset) => RegRecord {
+            tag: RegTag::CfaVal,
+            reg: 0,
+            operand: *offset as i64,
+        },
+        Register::Register(mreg, offset) => RegRecord {
+            tag: RegTag::RegOffset,
+            reg: reg_id(*mreg),
+            operand: *offset as i64,
+        },
+        Register::Expr(bytes, _) | Register::ValExpr(bytes, _) => {
+            let start = exprs.len();
+            exprs.extend_from_slice(bytes);
+            let tag = if matches!(reg, Register::Expr(..)) {
+                RegTag::ExprDeref
+            } else {
+                RegTag::ExprVal
+            };
+            RegRecord {
+                tag,
+                reg: 0,
+                operand: ((start as i64) << 32) | bytes.len() as i64,
+            }
+        }
+        Register::Unimplemented => RegRecord {
+            tag: RegTag::Unimplemented,
+            reg: 0,
+            operand: 0,
+        },
+    }
+}
+
+/// DWARF register numbers this arch maps to a tracked `MachineRegister`,
+/// used to generate the runtime register-lookup switch.
+fn known_dwarf_registers(arch: Arch) -> Vec<(u16, MachineRegister)> {
+    (0u16..64)
+        .filter_map(|n| arch.machine_register(gimli::Register(n)).map(|m| (n, m)))
+        .collect()
+}
+
+impl UnwindTable {
+    /// Alternative to `gen` that emits the rows as a sorted static data
+    /// array plus one fixed interpreter, instead of a balanced binary-search
+    /// tree of `if`/`else` C statements. Trades a modest per-frame
+    /// interpretation cost for output that adds one fixed-size data record
+    /// per row rather than a branch of code.
+    pub fn gen_table<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut exprs = Vec::new();
+        let records: Vec<_> = self
+            .rows
+            .iter()
+            .map(|row| {
+                (
+                    row.start_address,
+                    encode_register(&row.cfa, &mut exprs),
+                    encode_register(&row.fp, &mut exprs),
+                    encode_register(&row.ra, &mut exprs),
+                )
+            })
+            .collect();
+
+        gen_table_types(w, self.arch)?;
+
+        writeln!(
+            w,
+            "static const uint8_t _eh_elf_exprs[{}] = {{{}}};",
+            exprs.len().max(1),
+            exprs
+                .iter()
+                .map(|b| b.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )?;
+        writeln!(
+            w,
+            "static const unwind_row_t _eh_elf_rows[{}] = {{",
+            records.len().max(1)
+        )?;
+        for (start, cfa, fp, ra) in &records {
+            writeln!(
+                w,
+                "    {{0x{:x}u, {},{},{}, {},{},{}, {},{},{}}},",
+                start,
+                cfa.tag as u8,
+                cfa.reg,
+                cfa.operand,
+                fp.tag as u8,
+                fp.reg,
+                fp.operand,
+                ra.tag as u8,
+                ra.reg,
+                ra.operand,
+            )?;
+        }
+        if records.is_empty() {
+            writeln!(w, "    {{0, 0,0,0, 0,0,0, 0,0,0}},")?;
+        }
+        writeln!(w, "}};")?;
+        writeln!(
+            w,
+            "static const size_t _eh_elf_num_rows = {};",
+            records.len()
+        )?;
+
+        gen_interpreter(w, self.arch)?;
+        Ok(())
+    }
+}
+
+fn gen_table_types<W: Write>(w: &mut W, arch: Arch) -> Result<()> {
+    let (ip, sp, fp) = arch.ctx_fields();
+    writeln!(w, "#include <stddef.h>")?;
+    writeln!(w, "#include <stdint.h>")?;
+    writeln!(w)?;
+    writeln!(w, "typedef enum {{")?;
+    writeln!(w, "    UNWF_IP=0,")?;
+    writeln!(w, "    UNWF_SP=1,")?;
+    writeln!(w, "    UNWF_FP=2,")?;
+    writeln!(w, "    UNWF_ERROR=7,")?;
+    writeln!(w, "}} unwind_flags_t;")?;
+    writeln!(w)?;
+    writeln!(w, "typedef struct {{")?;
+    writeln!(w, "    uint8_t flags;")?;
+    writeln!(w, "    uintptr_t {}, {}, {};", ip, sp, fp)?;
+    writeln!(w, "}} unwind_context_t;")?;
+    writeln!(w)?;
+    writeln!(w, "typedef uintptr_t (*deref_func_t)(uintptr_t);")?;
+    writeln!(w)?;
+    writeln!(w, "typedef enum {{")?;
+    writeln!(w, "    RTAG_UNDEFINED=0,")?;
+    writeln!(w, "    RTAG_CFA_DEREF=1,")?;
+    writeln!(w, "    RTAG_CFA_VAL=2,")?;
+    writeln!(w, "    RTAG_REG_OFFSET=3,")?;
+    writeln!(w, "    RTAG_EXPR_DEREF=4,")?;
+    writeln!(w, "    RTAG_EXPR_VAL=5,")?;
+    writeln!(w, "    RTAG_UNIMPLEMENTED=6,")?;
+    writeln!(w, "}} reg_tag_t;")?;
+    writeln!(w)?;
+    writeln!(w, "typedef struct {{")?;
+    writeln!(w, "    uintptr_t start_address;")?;
+    writeln!(w, "    uint8_t cfa_tag, cfa_reg;")?;
+    writeln!(w, "    int64_t cfa_operand;")?;
+    writeln!(w, "    uint8_t fp_tag, fp_reg;")?;
+    writeln!(w, "    int64_t fp_operand;")?;
+    writeln!(w, "    uint8_t ra_tag, ra_reg;")?;
+    writeln!(w, "    int64_t ra_operand;")?;
+    writeln!(w, "}} unwind_row_t;")?;
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Emits the fixed, row-count-independent part: a tiny runtime interpreter
+/// for the DWARF expression subset `eval_dwarf_expr` (in `gen.rs`) also
+/// understands at compile time, the per-register rule interpreter, and the
+/// binary-search `_eh_elf` entry point.
+fn gen_interpreter<W: Write>(w: &mut W, arch: Arch) -> Result<()> {
+    let (ip, sp, fp) = arch.ctx_fields();
+    writeln!(w)?;
+    writeln!(
+        w,
+        "static uintptr_t _eh_elf_reg_value(unwind_context_t ctx, uint32_t reg, int *ok) {{"
+    )?;
+    writeln!(w, "    switch (reg) {{")?;
+    for (n, mreg) in known_dwarf_registers(arch) {
+        let field = match mreg {
+            MachineRegister::Sp => sp,
+            MachineRegister::Fp => fp,
+            MachineRegister::Ra => ip,
+        };
+        writeln!(w, "    case {}: return ctx.{};", n, field)?;
+    }
+    writeln!(w, "    default: *ok = 0; return 0;")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "}}")?;
+    writeln!(w)?;
+    writeln!(w, "// Interprets the DWARF stack-machine subset our compile-time")?;
+    writeln!(w, "// translator also understands: literals, bregN/bregx, plus/minus/")?;
+    writeln!(w, "// and/shl/shr/ge, plus_uconst, dup/pick/swap/drop, deref.")?;
+    writeln!(w, "static uintptr_t _eh_elf_eval_expr(")?;
+    writeln!(w, "    unwind_context_t ctx, const uint8_t *bytes, uint32_t len,")?;
+    writeln!(w, "    deref_func_t deref, int *ok")?;
+    writeln!(w, ") {{")?;
+    writeln!(w, "    uintptr_t stack[64]; int top = 0; uint32_t i = 0;")?;
+    writeln!(w, "    while (i < len) {{")?;
+    writeln!(w, "        uint8_t op = bytes[i++];")?;
+    writeln!(
+        w,
+        "        if (op >= 0x30 && op <= 0x4f) {{ if (top >= 64) {{ *ok = 0; return 0; }} stack[top++] = op - 0x30; continue; }}"
+    )?;
+    writeln!(w, "        if (op >= 0x70 && op <= 0x8f || op == 0x92) {{")?;
+    writeln!(w, "            uint32_t reg = op == 0x92 ? 0 : (uint32_t)(op - 0x70);")?;
+    writeln!(w, "            if (op == 0x92) {{")?;
+    writeln!(w, "                uint32_t shift = 0; uint8_t b;")?;
+    writeln!(w, "                do {{ b = bytes[i++]; reg |= (uint32_t)(b & 0x7f) << shift; shift += 7; }} while (b & 0x80);")?;
+    writeln!(w, "            }}")?;
+    writeln!(w, "            int64_t offset = 0; uint32_t shift = 0; uint8_t b;")?;
+    writeln!(w, "            do {{ b = bytes[i++]; offset |= (int64_t)(b & 0x7f) << shift; shift += 7; }} while (b & 0x80);")?;
+    writeln!(w, "            if (shift < 64 && (b & 0x40)) offset |= -((int64_t)1 << shift);")?;
+    writeln!(w, "            uintptr_t base = _eh_elf_reg_value(ctx, reg, ok);")?;
+    writeln!(w, "            if (!*ok || top >= 64) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            stack[top++] = base + (intptr_t)offset;")?;
+    writeln!(w, "        }} else if (op == 0x23) {{ // DW_OP_plus_uconst")?;
+    writeln!(w, "            if (top < 1) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            uint64_t value = 0; uint32_t shift = 0; uint8_t b;")?;
+    writeln!(w, "            do {{ b = bytes[i++]; value |= (uint64_t)(b & 0x7f) << shift; shift += 7; }} while (b & 0x80);")?;
+    writeln!(w, "            stack[top - 1] += value;")?;
+    writeln!(w, "        }} else if (op == 0x22 || op == 0x1c || op == 0x1a || op == 0x24 || op == 0x25 || op == 0x2a) {{")?;
+    writeln!(w, "            if (top < 2) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            uintptr_t b_ = stack[--top];")?;
+    writeln!(w, "            switch (op) {{")?;
+    writeln!(w, "            case 0x22: stack[top - 1] += b_; break;")?;
+    writeln!(w, "            case 0x1c: stack[top - 1] -= b_; break;")?;
+    writeln!(w, "            case 0x1a: stack[top - 1] &= b_; break;")?;
+    writeln!(w, "            case 0x24: stack[top - 1] <<= b_; break;")?;
+    writeln!(w, "            case 0x25: stack[top - 1] >>= b_; break;")?;
+    writeln!(w, "            case 0x2a: stack[top - 1] = stack[top - 1] >= b_; break;")?;
+    writeln!(w, "            }}")?;
+    writeln!(w, "        }} else if (op == 0x12) {{ // dup")?;
+    writeln!(w, "            if (top < 1 || top >= 64) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            stack[top] = stack[top - 1]; top++;")?;
+    writeln!(w, "        }} else if (op == 0x15) {{ // pick")?;
+    writeln!(w, "            uint8_t index = bytes[i++];")?;
+    writeln!(w, "            if (index >= top || top >= 64) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            stack[top] = stack[top - 1 - index]; top++;")?;
+    writeln!(w, "        }} else if (op == 0x16) {{ // swap")?;
+    writeln!(w, "            if (top < 2) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            uintptr_t t = stack[top - 1]; stack[top - 1] = stack[top - 2]; stack[top - 2] = t;")?;
+    writeln!(w, "        }} else if (op == 0x13) {{ // drop")?;
+    writeln!(w, "            if (top < 1) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            top--;")?;
+    writeln!(w, "        }} else if (op == 0x06) {{ // deref")?;
+    writeln!(w, "            if (top < 1) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "            stack[top - 1] = deref(stack[top - 1]);")?;
+    writeln!(w, "        }} else {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "    if (top < 1) {{ *ok = 0; return 0; }}")?;
+    writeln!(w, "    return stack[top - 1];")?;
+    writeln!(w, "}}")?;
+    writeln!(w)?;
+    writeln!(
+        w,
+        "static uintptr_t _eh_elf_reg(unwind_context_t ctx, unwind_context_t *out_ctx, uint8_t tag, uint8_t reg, int64_t operand, deref_func_t deref, int *ok) {{"
+    )?;
+    writeln!(w, "    switch (tag) {{")?;
+    writeln!(w, "    case RTAG_CFA_DEREF: return deref(out_ctx->{} + operand);", sp)?;
+    writeln!(w, "    case RTAG_CFA_VAL: return out_ctx->{} + operand;", sp)?;
+    writeln!(w, "    case RTAG_REG_OFFSET: {{")?;
+    writeln!(w, "        uintptr_t base = reg == 0 ? ctx.{} : reg == 1 ? ctx.{} : ctx.{};", sp, fp, ip)?;
+    writeln!(w, "        return base + operand;")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "    case RTAG_EXPR_DEREF:")?;
+    writeln!(w, "    case RTAG_EXPR_VAL: {{")?;
+    writeln!(w, "        uint32_t off = (uint32_t)(operand >> 32);")?;
+    writeln!(w, "        uint32_t len = (uint32_t)operand;")?;
+    writeln!(
+        w,
+        "        uintptr_t value = _eh_elf_eval_expr(ctx, _eh_elf_exprs + off, len, deref, ok);"
+    )?;
+    writeln!(w, "        return tag == RTAG_EXPR_DEREF ? deref(value) : value;")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "    default: *ok = 0; return 0;")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "}}")?;
+    writeln!(w)?;
+    writeln!(
+        w,
+        "void _eh_elf(unwind_context_t ctx, unwind_context_t *out_ctx, uintptr_t pc, deref_func_t deref) {{"
+    )?;
+    writeln!(w, "    if (_eh_elf_num_rows == 0) {{ out_ctx->flags = 1 << UNWF_ERROR; return; }}")?;
+    writeln!(w, "    size_t lo = 0, hi = _eh_elf_num_rows - 1;")?;
+    writeln!(w, "    while (lo < hi) {{")?;
+    writeln!(w, "        size_t mid = lo + (hi - lo + 1) / 2;")?;
+    writeln!(w, "        if (_eh_elf_rows[mid].start_address <= pc) lo = mid; else hi = mid - 1;")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "    const unwind_row_t *row = &_eh_elf_rows[lo];")?;
+    writeln!(w, "    uint8_t flags = 0;")?;
+    writeln!(w, "    int ok = 1;")?;
+    writeln!(
+        w,
+        "    if (row->cfa_tag != RTAG_UNDEFINED && row->cfa_tag != RTAG_UNIMPLEMENTED) {{"
+    )?;
+    writeln!(
+        w,
+        "        out_ctx->{} = _eh_elf_reg(ctx, out_ctx, row->cfa_tag, row->cfa_reg, row->cfa_operand, deref, &ok);",
+        sp
+    )?;
+    writeln!(w, "        if (ok) flags |= 1 << UNWF_SP;")?;
+    writeln!(w, "    }} else ok = 0;")?;
+    writeln!(
+        w,
+        "    if (ok && row->fp_tag != RTAG_UNDEFINED && row->fp_tag != RTAG_UNIMPLEMENTED) {{"
+    )?;
+    writeln!(
+        w,
+        "        out_ctx->{} = _eh_elf_reg(ctx, out_ctx, row->fp_tag, row->fp_reg, row->fp_operand, deref, &ok);",
+        fp
+    )?;
+    writeln!(w, "        if (ok) flags |= 1 << UNWF_FP;")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "    int ra_implemented = row->ra_tag != RTAG_UNIMPLEMENTED;")?;
+    writeln!(w, "    if (ok && ra_implemented && row->ra_tag != RTAG_UNDEFINED) {{")?;
+    writeln!(
+        w,
+        "        out_ctx->{} = _eh_elf_reg(ctx, out_ctx, row->ra_tag, row->ra_reg, row->ra_operand, deref, &ok);",
+        ip
+    )?;
+    writeln!(w, "        if (ok) flags |= 1 << UNWF_IP;")?;
+    writeln!(w, "    }}")?;
+    writeln!(w, "    if (!ok || !ra_implemented) flags |= 1 << UNWF_ERROR;")?;
+    writeln!(w, "    out_ctx->flags = flags;")?;
+    writeln!(w, "}}")?;
+    Ok(())
+}